@@ -3,6 +3,9 @@ use crate::bus::*;
 use crate::bus::prim::*;
 use crate::bus::task::*;
 
+pub mod timing;
+use timing::BusTiming;
+
 /// Interface used by the bus to perform some access on an I/O device.
 pub trait MmioDevice {
     /// Width of accesses supported on this device.
@@ -12,36 +15,163 @@ pub trait MmioDevice {
     fn read(&mut self, off: usize) -> BusPacket;
     /// Handle a write, optionally returning a task for the bus.
     fn write(&mut self, off: usize, val: Self::Width) -> Option<BusTask>;
+
+    /// Whether a narrower access may be synthesized against this device
+    /// by reading the native-width register, splicing in the new lane,
+    /// and writing the result back.
+    ///
+    /// Defaults to `false`: most of these registers are write-triggered
+    /// or otherwise side-effecting (a start/command bit, a latch, a
+    /// clear-on-read status field), and re-issuing a write derived from
+    /// a read of one can corrupt state or re-trigger an operation. A
+    /// device should only override this to `true` once its registers at
+    /// the relevant offsets are known to tolerate a read immediately
+    /// followed by a write-back of (almost) the same value.
+    fn rmw_safe(&self) -> bool { false }
+}
+
+/// Number of bytes covered by a [`BusWidth`].
+fn width_bytes(w: BusWidth) -> usize {
+    match w {
+        BusWidth::B => 1,
+        BusWidth::H => 2,
+        BusWidth::W => 4,
+    }
+}
+
+/// The [`BusWidth`] of an already-constructed [`BusPacket`].
+fn packet_width(p: &BusPacket) -> BusWidth {
+    match p {
+        BusPacket::Byte(_) => BusWidth::B,
+        BusPacket::Half(_) => BusWidth::H,
+        BusPacket::Word(_) => BusWidth::W,
+    }
+}
+
+/// Read a [`BusPacket`] out as a plain `u32`, regardless of its width.
+fn packet_as_u32(p: &BusPacket) -> u32 {
+    match p {
+        BusPacket::Byte(v) => *v as u32,
+        BusPacket::Half(v) => *v as u32,
+        BusPacket::Word(v) => *v,
+    }
+}
+
+/// Extract a `width`-sized lane out of a native access, honoring the
+/// bus's big-endian byte order (byte/half 0 is the most-significant).
+fn extract_lane(native_val: u32, native_bytes: usize, width: BusWidth, lane_off: usize) -> BusPacket {
+    let width_bytes = width_bytes(width);
+    let shift = ((native_bytes - width_bytes - lane_off) * 8) as u32;
+    match width {
+        BusWidth::B => BusPacket::Byte((native_val >> shift) as u8),
+        BusWidth::H => BusPacket::Half((native_val >> shift) as u16),
+        BusWidth::W => BusPacket::Word(native_val >> shift),
+    }
+}
+
+/// Splice a narrower write into a native-width value, at the lane
+/// selected by `lane_off` (again, big-endian).
+fn merge_lane(native_val: u32, native_bytes: usize, lane_off: usize, msg: &BusPacket) -> u32 {
+    let width_bytes = width_bytes(packet_width(msg));
+    let shift = ((native_bytes - width_bytes - lane_off) * 8) as u32;
+    let lane_mask: u32 = if width_bytes >= 4 { u32::MAX } else { ((1u32 << (width_bytes * 8)) - 1) << shift };
+    (native_val & !lane_mask) | ((packet_as_u32(msg) << shift) & lane_mask)
 }
 
 impl Bus {
+    /// The native access width of a memory-mapped I/O device.
+    fn native_width(&self, dev: IoDevice) -> BusWidth {
+        use IoDevice::*;
+        match dev {
+            Mi | Ddr => BusWidth::H,
+            _ => BusWidth::W,
+        }
+    }
+
+    /// Whether a sub-native-width write to `dev` can be synthesized with
+    /// a read-modify-write.
+    fn rmw_safe(&self, dev: IoDevice) -> bool {
+        use IoDevice::*;
+        match dev {
+            Nand  => self.nand.rmw_safe(),
+            Aes   => self.aes.rmw_safe(),
+            Sha   => self.sha.rmw_safe(),
+            Ehci  => self.ehci.rmw_safe(),
+            Ohci0 => self.ohci0.rmw_safe(),
+            Ohci1 => self.ohci1.rmw_safe(),
+            Sdhc0 => self.sd0.rmw_safe(),
+            Sdhc1 => self.sd1.rmw_safe(),
+            Hlwd  => self.hlwd.rmw_safe(),
+            Ahb   => self.hlwd.ahb.rmw_safe(),
+            Di    => self.hlwd.di.rmw_safe(),
+            Exi   => self.hlwd.exi.rmw_safe(),
+            Mi    => self.hlwd.mi.rmw_safe(),
+            Ddr   => self.hlwd.ddr.rmw_safe(),
+            _ => false,
+        }
+    }
+
+    /// Dispatch a read at the device's native width.
+    fn native_read(&mut self, dev: IoDevice, off: usize) -> BusPacket {
+        use IoDevice::*;
+        match dev {
+            Nand  => self.nand.read(off),
+            Aes   => self.aes.read(off),
+            Sha   => self.sha.read(off),
+            Ehci  => self.ehci.read(off),
+            Ohci0 => self.ohci0.read(off),
+            Ohci1 => self.ohci1.read(off),
+            Sdhc0 => self.sd0.read(off),
+            Sdhc1 => self.sd1.read(off),
+
+            Hlwd  => self.hlwd.read(off),
+            Ahb   => self.hlwd.ahb.read(off),
+            Di    => self.hlwd.di.read(off),
+            Exi   => self.hlwd.exi.read(off),
+            Mi    => self.hlwd.mi.read(off),
+            Ddr   => self.hlwd.ddr.read(off),
+            _ => panic!("Unsupported read for {:?} at {:x}", dev, off),
+        }
+    }
+
     /// Dispatch a physical read access to some memory-mapped I/O device.
+    ///
+    /// When `width` is narrower than the device's native width, this
+    /// performs the native-width access and extracts the requested lane
+    /// (honoring the bus's big-endian byte order) instead of panicking.
     pub fn do_mmio_read(&mut self, dev: IoDevice, off: usize, width: BusWidth) -> BusPacket {
-        use IoDevice::*;
-        match (width, dev) {
-            (BusWidth::W, Nand)  => self.nand.read(off),
-            (BusWidth::W, Aes)   => self.aes.read(off),
-            (BusWidth::W, Sha)   => self.sha.read(off),
-            (BusWidth::W, Ehci)  => self.ehci.read(off),
-            (BusWidth::W, Ohci0) => self.ohci0.read(off),
-            (BusWidth::W, Ohci1) => self.ohci1.read(off),
-            (BusWidth::W, Sdhc0) => self.sd0.read(off),
-            (BusWidth::W, Sdhc1) => self.sd1.read(off),
-
-            (BusWidth::W, Hlwd)  => self.hlwd.read(off),
-            (BusWidth::W, Ahb)   => self.hlwd.ahb.read(off),
-            (BusWidth::W, Di)    => self.hlwd.di.read(off),
-            (BusWidth::W, Exi)   => self.hlwd.exi.read(off),
-            (BusWidth::H, Mi)    => self.hlwd.mi.read(off),
-            (BusWidth::H, Ddr)   => self.hlwd.ddr.read(off),
-            _ => panic!("Unsupported read {:?} for {:?} at {:x}", width, dev, off),
+        let native_width = self.native_width(dev);
+        if width_bytes(width) > width_bytes(native_width) {
+            panic!("Unsupported read {:?} for {:?} at {:x}", width, dev, off);
         }
+        if width == native_width {
+            return self.native_read(dev, off);
+        }
+
+        let aligned_off = off & !(width_bytes(native_width) - 1);
+        let native = self.native_read(dev, aligned_off);
+        extract_lane(packet_as_u32(&native), width_bytes(native_width), width, off - aligned_off)
     }
 
-    /// Dispatch a physical write access to some memory-mapped I/O device.
-    pub fn do_mmio_write(&mut self, dev: IoDevice, off: usize, msg: BusPacket) {
+    /// Dispatch a write at the device's native width, scheduling
+    /// whatever [`BusTask`] the device returns.
+    fn native_write(&mut self, dev: IoDevice, off: usize, msg: BusPacket) {
         use IoDevice::*;
         use BusPacket::*;
+
+        // Control words like NAND_CTRL/AES_CTRL/SHA_CTRL pack a
+        // block/byte count into their low bits alongside command and
+        // flag bits higher up (e.g. Starlet's NAND_CTRL uses bits 0-11
+        // for the transfer length). Mask down to that low field instead
+        // of treating the whole 32-bit write as a length, since the
+        // latter would let command/flag bits blow the cost model's
+        // length-derived latency up to something absurd.
+        //
+        // NOTE: this is still only a size *hint* - it's close enough to
+        // make polling-on-busy-bit software behave plausibly, not a
+        // cycle-accurate decode of any particular register's layout.
+        let transfer_len = (packet_as_u32(&msg) & 0x0fff) as usize;
+
         let task = match (msg, dev) {
             (Word(val), Nand)  => self.nand.write(off, val),
             (Word(val), Aes)   => self.aes.write(off, val),
@@ -52,7 +182,6 @@ impl Bus {
             (Word(val), Sdhc0) => self.sd0.write(off, val),
             (Word(val), Sdhc1) => self.sd1.write(off, val),
 
-
             (Word(val), Hlwd)  => self.hlwd.write(off, val),
             (Word(val), Ahb)   => self.hlwd.ahb.write(off, val),
             (Word(val), Exi)   => self.hlwd.exi.write(off, val),
@@ -63,21 +192,53 @@ impl Bus {
             _ => panic!("Unsupported write {:?} for {:?} at {:x}", msg, dev, off),
         };
 
-        // If the device returned some task, schedule it
-        if task.is_some() {
-            let t = task.unwrap();
-            let c = match t {
-                BusTask::Nand(_) => 0,
-                BusTask::Aes(_) => 0,
-                BusTask::Sha(_) => 0,
-
-                BusTask::Mi{..} => 0,
-                BusTask::SetRomDisabled(_) => 0,
-                BusTask::SetMirrorEnabled(_) => 0,
-            };
+        // If the device returned some task, schedule it to drain after a
+        // latency appropriate to its kind, instead of instantaneously.
+        if let Some(t) = task {
+            let c = self.timing.cost(&t, transfer_len);
             self.tasks.push(Task { kind: t, target_cycle: self.cycle + c });
         }
     }
+
+    /// Dispatch a physical write access to some memory-mapped I/O device.
+    ///
+    /// When `msg` is narrower than the device's native width, this does
+    /// a read-modify-write of the native register if the device allows it
+    /// via [`MmioDevice::rmw_safe`]. That flag defaults to `false`, and a
+    /// narrow write to a device that does not set it still panics.
+    pub fn do_mmio_write(&mut self, dev: IoDevice, off: usize, msg: BusPacket) {
+        let native_width = self.native_width(dev);
+        let req_width = packet_width(&msg);
+        if width_bytes(req_width) > width_bytes(native_width) {
+            panic!("Unsupported write {:?} for {:?} at {:x}", msg, dev, off);
+        }
+        if req_width == native_width {
+            return self.native_write(dev, off, msg);
+        }
+        if !self.rmw_safe(dev) {
+            panic!("Unsupported write {:?} for {:?} at {:x} (narrow access requires a read-modify-write, which this device disallows)",
+                msg, dev, off);
+        }
+
+        let aligned_off = off & !(width_bytes(native_width) - 1);
+        let native = self.native_read(dev, aligned_off);
+        let merged = merge_lane(packet_as_u32(&native), width_bytes(native_width), off - aligned_off, &msg);
+        let merged_packet = match native_width {
+            BusWidth::B => BusPacket::Byte(merged as u8),
+            BusWidth::H => BusPacket::Half(merged as u16),
+            BusWidth::W => BusPacket::Word(merged),
+        };
+        self.native_write(dev, aligned_off, merged_packet);
+    }
+
+    /// Override the cost model used to schedule bus task latency.
+    ///
+    /// This lets callers trade timing accuracy for raw speed (e.g. in
+    /// tests, or when booting code that doesn't care about busy-polling
+    /// semantics) by substituting a near-zero-cost `BusTiming`.
+    pub fn set_timing(&mut self, timing: BusTiming) {
+        self.timing = timing;
+    }
 }
 
 
@@ -0,0 +1,64 @@
+//! Cost model for scheduled bus tasks.
+//!
+//! Previously every `BusTask` completed on the very next `step()`
+//! (`target_cycle = self.cycle`), so software polling a busy bit before
+//! a NAND/AES/SHA operation finished would never actually see it set.
+//! `BusTiming` gives each kind of task a plausible latency so that kind
+//! of timing-sensitive polling behaves the way it does on real hardware.
+
+use crate::bus::task::BusTask;
+
+/// Cycle costs used to compute how long a scheduled `BusTask` takes to
+/// drain. Values are round numbers picked to be "slow enough to poll",
+/// not cycle-accurate measurements of actual silicon.
+#[derive(Debug, Clone, Copy)]
+pub struct BusTiming {
+    /// Fixed latency for a NAND command (seek/setup), before per-page
+    /// cost is added.
+    pub nand_base_cycles: usize,
+    /// Additional cycles per 2KiB NAND page transferred.
+    pub nand_cycles_per_page: usize,
+    /// Cycles to process one 16-byte AES block.
+    pub aes_cycles_per_block: usize,
+    /// Cycles to process one 64-byte SHA block.
+    pub sha_cycles_per_block: usize,
+    /// Cycles for an MI refresh cycle to complete.
+    pub mi_refresh_cycles: usize,
+}
+
+impl Default for BusTiming {
+    fn default() -> Self {
+        BusTiming {
+            nand_base_cycles: 512,
+            nand_cycles_per_page: 48,
+            aes_cycles_per_block: 12,
+            sha_cycles_per_block: 18,
+            mi_refresh_cycles: 128,
+        }
+    }
+}
+
+impl BusTiming {
+    /// Number of cycles until a scheduled task of this kind should
+    /// drain, given the length (in bytes) of the transfer that
+    /// triggered it (as encoded in the MMIO write that scheduled it).
+    pub fn cost(&self, task: &BusTask, transfer_len: usize) -> usize {
+        match task {
+            BusTask::Nand(_) => {
+                let pages = (transfer_len / 2048).max(1);
+                self.nand_base_cycles + self.nand_cycles_per_page * pages
+            },
+            BusTask::Aes(_) => {
+                let blocks = (transfer_len / 16).max(1);
+                self.aes_cycles_per_block * blocks
+            },
+            BusTask::Sha(_) => {
+                let blocks = (transfer_len / 64).max(1);
+                self.sha_cycles_per_block * blocks
+            },
+            BusTask::Mi { .. } => self.mi_refresh_cycles,
+            BusTask::SetRomDisabled(_) => 0,
+            BusTask::SetMirrorEnabled(_) => 0,
+        }
+    }
+}
@@ -9,31 +9,10 @@ use crate::cpu::Cpu;
 use crate::cpu::reg::Reg;
 use crate::cpu::mmu::prim::{Access, TLBReq};
 
-/// NOTE: `skyeye-starlet` does something like this; wonder if there's a
-/// better way of keeping track of the threads?
-#[derive(Debug)]
-pub enum ExecutionCtx {
-    UNK,
-    CRY,
-    ES,
-    FS,
-    KRN,
-}
-impl From<u32> for ExecutionCtx {
-    fn from(pc: u32) -> Self {
-        use ExecutionCtx::*;
-        match (pc & 0xffff_0000) >> 16 {
-            0x1386 => CRY,
-            0x2000 => FS,
-            0x2010 => ES,
-            0xffff => KRN,
-            _ => UNK,
-        }
-    }
-}
+pub mod thread;
+use thread::BlockReason;
 
-
-/// Typed arguments to a syscall. 
+/// Typed arguments to a syscall.
 pub enum ArgType { Ptr, StrPtr, Int, Uint }
 
 /// Format arguments for some IOS syscall.
@@ -44,21 +23,21 @@ pub struct SyscallDef {
 
 /// Shorthand for declaring a syscall definition.
 macro_rules! scdef {
-    ($name:literal, $($arg:ident),*) => {
-        SyscallDef { name: $name, arg: &[$(ArgType::$arg,)*] } 
+    ($name:literal $(, $arg:ident)*) => {
+        SyscallDef { name: $name, arg: &[$(ArgType::$arg,)*] }
     }
 }
 
 pub fn get_syscall_desc(idx: u32) -> SyscallDef {
     match idx {
-        0x02 => scdef!("ThreadCancel", ),
-        0x04 => scdef!("ThreadGetPid", ),
+        0x02 => scdef!("ThreadCancel"),
+        0x04 => scdef!("ThreadGetPid"),
         0x09 => scdef!("ThreadSetPrio", Int, Int),
         0x0a => scdef!("MqueueCreate", Ptr, Int),
         0x0b => scdef!("MqueueDestroy", Ptr),
-        //0x0e => scdef!("MqueueRecv", Ptr, Uint),
+        0x0e => scdef!("MqueueRecv", Ptr, Uint),
         0x0f => scdef!("MqueueRegisterHandler", Int, Int, Uint),
-        //0x10 => scdef!("MqueueDestroyHandler", Ptr, Ptr, Ptr),
+        0x10 => scdef!("MqueueDestroyHandler", Ptr, Ptr, Ptr),
         0x11 => scdef!("TimerCreate", Int, Int, Int, Uint),
         0x18 => scdef!("HeapAlloc", Int, Uint),
         0x1c => scdef!("Open", StrPtr, Int),
@@ -99,12 +78,68 @@ pub fn read_string(cpu: &Cpu, ptr: u32) -> String {
 }
 
 
+/// Address of IOS's "current thread" scheduler variable.
+///
+/// NOTE: like everything else in this file, this is specific to IOS58's
+/// layout; a different kernel build would need a different address.
+const CUR_THREAD_ADDR: u32 = 0xffff_7000;
+
+/// Read the kernel's own notion of which thread is currently running.
+///
+/// This is the thing that makes `ThreadTable::current` authoritative
+/// instead of guessed: we read it directly out of the kernel's
+/// scheduler state on every syscall, rather than trying to infer it
+/// from syscall arguments.
+fn read_current_tid(cpu: &Cpu) -> i32 {
+    let paddr = cpu.mmu.translate(TLBReq::new(CUR_THREAD_ADDR, Access::Debug));
+    let mut buf = [0u8; 4];
+    cpu.mmu.bus.write().unwrap().dma_read(paddr, &mut buf);
+    i32::from_be_bytes(buf)
+}
+
+/// Update the thread table for syscalls that create, schedule, or block
+/// IOS threads, using the (still-unresolved) arguments in `cpu`'s
+/// registers. This runs regardless of whether the syscall ends up being
+/// logged.
+///
+/// NOTE: this only approximates *why* a thread stopped running, since we
+/// don't model the full IPC/scheduling state machine; it's good enough
+/// to tell threads apart during boot, not a substitute for a real
+/// scheduler trace.
+fn update_thread_state(cpu: &Cpu, idx: u32) {
+    let tid = thread::current_tid().unwrap_or(-1);
+    match idx {
+        0x04 => thread::touch(tid), // ThreadGetPid
+        0x09 => { // ThreadSetPrio(thread, prio)
+            let target = if cpu.reg[0] as i32 == 0 { tid } else { cpu.reg[0] as i32 };
+            thread::set_prio(target, cpu.reg[1] as i32);
+        },
+        0x0a => thread::touch(tid), // MqueueCreate
+        0x0b => thread::touch(tid), // MqueueDestroy
+        0x0e => { // MqueueRecv(qid, flags)
+            thread::block(tid, BlockReason::BlockedOnMqueue { qid: cpu.reg[0] as i32 });
+        },
+        0x0f => thread::touch(tid), // MqueueRegisterHandler
+        0x10 => thread::touch(tid), // MqueueDestroyHandler
+        0x11 => thread::touch(tid), // TimerCreate: synchronous, doesn't block the caller
+        0x2a => { // ResourceReply: typical IOS resource-manager threads
+            // loop { recv; ...; reply(); }, so replying puts the thread
+            // straight back into waiting for its next request.
+            thread::block(tid, BlockReason::BlockedOnResourceReply);
+        },
+        _ => {},
+    }
+}
+
 /// Resolve information about an IOS syscall and its arguments.
 pub fn resolve_syscall(cpu: &mut Cpu, opcd: u32) {
     // Get the syscall index (and ignore some
     let idx = (opcd & 0x00ff_ffe0) >> 5;
-    match idx { 
-        0x0e | // MqueueRecv
+
+    thread::on_context_switch(read_current_tid(cpu));
+    update_thread_state(cpu, idx);
+
+    match idx {
         0x2f | // AhbMemFlush
         0x30 | // CcAhbMemFlush
         0x3f | // SyncBeforeRead
@@ -138,8 +173,9 @@ pub fn resolve_syscall(cpu: &mut Cpu, opcd: u32) {
             arg_buf.push_str(", ");
         }
     }
-    println!("IOS [{:?}] {}({}) (lr={:08x})", 
-        ExecutionCtx::from(cpu.read_fetch_pc()),
-        syscall.name, arg_buf, cpu.reg[Reg::Lr]
+
+    let tid = thread::current_tid().unwrap_or(-1);
+    println!("IOS [tid={}] {}({}) (lr={:08x})",
+        tid, syscall.name, arg_buf, cpu.reg[Reg::Lr]
     );
 }
@@ -0,0 +1,128 @@
+//! A table tracking the state of IOS threads.
+//!
+//! This replaces the old trick of guessing the running module from the
+//! top bits of the PC (which can't tell two threads sharing a code region
+//! apart). Instead we maintain a table keyed by IOS thread id and update
+//! it by watching the syscalls that create, schedule, and block threads.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+pub type ThreadId = i32;
+
+/// Scheduling state of an IOS thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Running,
+    Ready,
+    Stopped,
+}
+
+/// Why a non-running thread is blocked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockReason {
+    BlockedOnMqueue { qid: i32 },
+    BlockedOnResourceReply,
+}
+
+/// Everything we know about a single IOS thread.
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadInfo {
+    pub prio: i32,
+    pub state: ThreadState,
+    pub block: Option<BlockReason>,
+}
+impl Default for ThreadInfo {
+    fn default() -> Self {
+        ThreadInfo { prio: 0, state: ThreadState::Ready, block: None }
+    }
+}
+
+/// A table of all known IOS threads, keyed by thread id.
+pub struct ThreadTable {
+    threads: HashMap<ThreadId, ThreadInfo>,
+    current: Option<ThreadId>,
+}
+impl ThreadTable {
+    fn new() -> Self {
+        ThreadTable { threads: HashMap::new(), current: None }
+    }
+
+    fn entry(&mut self, tid: ThreadId) -> &mut ThreadInfo {
+        self.threads.entry(tid).or_insert_with(ThreadInfo::default)
+    }
+
+    /// Record that `tid` is now the thread actually running on the core.
+    ///
+    /// NOTE: this is called on every syscall entry with the thread id
+    /// read from the kernel's current-thread variable (syscall arguments
+    /// alone can't tell us this). The context-switch path itself is not
+    /// watched, so a switch is only seen at the next syscall.
+    pub fn on_context_switch(&mut self, tid: ThreadId) {
+        if let Some(prev) = self.current {
+            if prev != tid {
+                let prev_info = self.entry(prev);
+                if prev_info.state == ThreadState::Running {
+                    prev_info.state = ThreadState::Ready;
+                }
+            }
+        }
+        let info = self.entry(tid);
+        info.state = ThreadState::Running;
+        info.block = None;
+        self.current = Some(tid);
+    }
+
+    pub fn set_prio(&mut self, tid: ThreadId, prio: i32) {
+        self.entry(tid).prio = prio;
+    }
+
+    /// Mark `tid` as stopped/blocked on some resource.
+    pub fn block(&mut self, tid: ThreadId, reason: BlockReason) {
+        let info = self.entry(tid);
+        info.state = ThreadState::Stopped;
+        info.block = Some(reason);
+    }
+
+    /// Ensure `tid` has a table entry, without changing its state.
+    pub fn touch(&mut self, tid: ThreadId) {
+        self.entry(tid);
+    }
+
+    pub fn current(&self) -> Option<ThreadId> { self.current }
+
+    pub fn status(&self, tid: ThreadId) -> Option<ThreadInfo> {
+        self.threads.get(&tid).copied()
+    }
+}
+
+fn table() -> &'static Mutex<ThreadTable> {
+    static TABLE: OnceLock<Mutex<ThreadTable>> = OnceLock::new();
+    TABLE.get_or_init(|| Mutex::new(ThreadTable::new()))
+}
+
+/// Query the current state of some IOS thread.
+pub fn thread_status(tid: ThreadId) -> Option<ThreadInfo> {
+    table().lock().unwrap().status(tid)
+}
+
+/// The thread id the core is currently executing, if known.
+pub fn current_tid() -> Option<ThreadId> {
+    table().lock().unwrap().current()
+}
+
+pub fn on_context_switch(tid: ThreadId) {
+    table().lock().unwrap().on_context_switch(tid);
+}
+
+pub fn set_prio(tid: ThreadId, prio: i32) {
+    table().lock().unwrap().set_prio(tid, prio);
+}
+
+pub fn touch(tid: ThreadId) {
+    table().lock().unwrap().touch(tid);
+}
+
+pub fn block(tid: ThreadId, reason: BlockReason) {
+    table().lock().unwrap().block(tid, reason);
+}